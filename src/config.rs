@@ -1,15 +1,24 @@
 //! # Config module.
 //!
 //! The Config module provides the beamium configuration.
-//! It set defaults and then load config from '/etc', local dir and provided path.
+//! It set defaults and then load config from '/etc', local dir and provided path. Each of
+//! '/etc', the local dir and the provided path's directory may also carry a `config.d/`
+//! directory of `*.yaml` fragments, merged in lexicographic order after the corresponding main
+//! file.
+//! String values may reference environment variables with `$VAR` / `${VAR}`, expanded at
+//! load time (use `$$` for a literal `$`).
+use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io;
 use std::fmt;
 use std::string::String;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::error;
 use std::error::Error;
+use std::env;
+use std::cmp;
+use std::net::SocketAddr;
 use yaml_rust::{YamlLoader, ScanError};
 use cast;
 use std::collections::HashMap;
@@ -24,6 +33,25 @@ pub struct Config {
     pub sinks: Vec<Sink>,
     pub labels: HashMap<String, String>,
     pub parameters: Parameters,
+    pub origins: HashMap<String, ConfigOrigin>,
+}
+
+impl Config {
+    /// Return the file and dotted key a resolved value ultimately came from, if any.
+    ///
+    /// Useful to debug which layer (defaults, `/etc`, local dir, explicit path) won when
+    /// several files set the same key.
+    pub fn origin_of(&self, key: &str) -> Option<&ConfigOrigin> {
+        self.origins.get(key)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+/// Identifies the file and dotted config key a resolved value came from.
+pub struct ConfigOrigin {
+    pub file: PathBuf,
+    pub key: String,
 }
 
 #[derive(Debug)]
@@ -58,6 +86,31 @@ pub struct Sink {
     pub size: u64,
 }
 
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+/// Overrides collected from the command line.
+///
+/// `config`, if set, replaces `load_config`'s `config_path` argument and so is resolved before
+/// any file is loaded, not after. `large_config` lifts the config-file size limit while files
+/// are read. Every other field is applied as the highest-precedence layer,
+/// once every config file has been merged, so a flag always wins over whatever the files say.
+/// `verbose` and `quiet` are repeat counts (one per `-v`/`-q`) and are mutually exclusive.
+pub struct CliOverrides {
+    pub verbose: u64,
+    pub quiet: u64,
+    pub config: Option<String>,
+    pub scan_period: Option<u64>,
+    pub timeout: Option<u64>,
+    pub batch_size: Option<u64>,
+    pub log_file: Option<String>,
+    pub large_config: bool,
+}
+
+/// Default maximum size, in bytes, a config file may have before `load_config` refuses to
+/// read it. Bypassed by `CliOverrides.large_config` (`--large-config`).
+pub const DEFAULT_MAX_CONFIG_SIZE: u64 = 100 * 1024 * 1024;
+
 #[derive(Debug)]
 #[derive(Clone)]
 /// Parameters config.
@@ -70,6 +123,17 @@ pub struct Parameters {
     pub log_file: String,
     pub log_level: slog::Level,
     pub timeout: u64,
+    pub telemetry: Option<Telemetry>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+/// Self-telemetry config.
+///
+/// When set, beamium exposes its own liveness and internal counters over HTTP: a `/health`
+/// probe and a `/metrics` endpoint in Prometheus format.
+pub struct Telemetry {
+    pub listen: SocketAddr,
 }
 
 #[derive(Debug)]
@@ -145,9 +209,32 @@ impl error::Error for ConfigError {
 
 /// Load config.
 ///
-/// Setup a defaults config and then load config from '/etc', local dir and provided path.
-/// Return Err if provided path is not found or if config is unprocessable.
-pub fn load_config(config_path: &str) -> Result<Config, ConfigError> {
+/// Setup a defaults config and then load config files on top of it, each applied as an ordered
+/// layer so a later layer overrides a value set by an earlier one. With an empty path the layers
+/// are defaults < `/etc` < local dir; with an explicit path they are defaults < explicit path,
+/// and neither `/etc` nor the local dir is read. Each main file is followed by its own `config.d/`
+/// fragment directory (`/etc/beamium/config.d`, `config.d`, or, for an explicit path, a
+/// `config.d` alongside it), so the fragment convention applies no matter how the main file was
+/// selected. `cli.config`, if set, replaces `config_path` before any file is loaded, and
+/// `cli.large_config` lifts the size limit while files are read; every other `cli` field is
+/// applied last, above every file. Return Err if the resolved path is not found, if `cli` is
+/// inconsistent, or if config is unprocessable.
+pub fn load_config(config_path: &str, cli: &CliOverrides) -> Result<Config, ConfigError> {
+    if cli.verbose > 0 && cli.quiet > 0 {
+        return Err("-v and -q are mutually exclusive".into());
+    }
+
+    let config_path = match cli.config {
+        Some(ref config) => config.as_str(),
+        None => config_path,
+    };
+
+    let max_size = if cli.large_config {
+        None
+    } else {
+        Some(DEFAULT_MAX_CONFIG_SIZE)
+    };
+
     // Defaults
     let mut config = Config {
         sources: Vec::new(),
@@ -162,65 +249,281 @@ pub fn load_config(config_path: &str) -> Result<Config, ConfigError> {
             log_file: String::from(env!("CARGO_PKG_NAME")) + ".log",
             log_level: slog::Level::Info,
             timeout: 300,
+            telemetry: None,
         },
+        origins: HashMap::new(),
     };
 
     if config_path.is_empty() {
         // Load from etc
         if Path::new("/etc/beamium/config.yaml").exists() {
-            try!(load_path("/etc/beamium/config.yaml", &mut config));
+            try!(load_path("/etc/beamium/config.yaml", &mut config, max_size));
         }
+        try!(load_config_dir("/etc/beamium/config.d", &mut config, max_size));
 
         // Load local
         if Path::new("config.yaml").exists() {
-            try!(load_path("config.yaml", &mut config));
+            try!(load_path("config.yaml", &mut config, max_size));
         }
+        try!(load_config_dir("config.d", &mut config, max_size));
     } else {
         // Load from provided path
-        try!(load_path(config_path, &mut config));
+        try!(load_path(config_path, &mut config, max_size));
+
+        let config_dir = Path::new(config_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join("config.d");
+        try!(load_config_dir(config_dir, &mut config, max_size));
     }
 
+    try!(apply_cli_overrides(&mut config, cli));
+
     Ok(config)
 }
 
+/// Validate a config file without starting any work.
+///
+/// Runs the full parse-and-merge pipeline used at startup (size guard, YAML parsing, regex
+/// compilation for `metrics`/`selector`, env-var expansion) and discards the resulting
+/// `Config`, so operators can check a config is well-formed, e.g. in CI before deploying.
+pub fn validate_config(config_path: &str) -> Result<(), ConfigError> {
+    try!(load_config(config_path, &CliOverrides::default()));
+    Ok(())
+}
+
+/// Expand `$VAR` / `${VAR}` references in a config value against the process environment.
+///
+/// A literal `$` is escaped by doubling it (`$$`). `key` is the dotted config key the value
+/// came from, used to qualify the error when a referenced variable is unset.
+fn expand_env(value: &str, key: &str) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek().cloned() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            }
+            Some('{') => {
+                chars.next();
+                let mut name = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+
+                if !closed {
+                    return Err(format!("{} references an unterminated \"${{\" (missing closing \
+                                         '}}')",
+                                        key)
+                        .into());
+                }
+
+                out.push_str(&try!(env::var(&name)
+                    .map_err(|_| format!("{} references undefined env var {}", key, name))));
+            }
+            Some(c2) if c2.is_alphabetic() || c2 == '_' => {
+                let mut name = String::new();
+                while let Some(&c2) = chars.peek() {
+                    if c2.is_alphanumeric() || c2 == '_' {
+                        name.push(c2);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                out.push_str(&try!(env::var(&name)
+                    .map_err(|_| format!("{} references undefined env var {}", key, name))));
+            }
+            _ => out.push('$'),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Step `base` up by `verbose` levels and down by `quiet` levels, clamping to the range
+/// `Critical..=Trace`. One `-v` raises verbosity a step (e.g. Info -> Debug), one `-q` lowers
+/// it (e.g. Info -> Warning); `verbose` and `quiet` are assumed mutually exclusive by the
+/// caller. `slog::Level` has no silent/"off" variant, so `Critical` is the floor: stacking
+/// `-q` beyond that still logs Criticals, it does not mute logging entirely.
+fn step_log_level(base: slog::Level, verbose: u64, quiet: u64) -> Result<slog::Level, ConfigError> {
+    let min = slog::Level::Critical.as_usize() as i64;
+    let max = slog::Level::Trace.as_usize() as i64;
+    let level = cmp::max(min,
+                          cmp::min(max, base.as_usize() as i64 + verbose as i64 - quiet as i64));
+
+    slog::Level::from_usize(level as usize).ok_or_else(|| "invalid log level".into())
+}
+
+/// Apply `cli`, the highest-precedence layer, on top of an already file-merged `config`.
+fn apply_cli_overrides(config: &mut Config, cli: &CliOverrides) -> Result<(), ConfigError> {
+    let cli_origin = Path::new("<cli>");
+
+    if let Some(scan_period) = cli.scan_period {
+        config.parameters.scan_period = scan_period;
+        record_origin(config, cli_origin, String::from("parameters.scan-period"));
+    }
+
+    if let Some(timeout) = cli.timeout {
+        config.parameters.timeout = timeout;
+        record_origin(config, cli_origin, String::from("parameters.timeout"));
+    }
+
+    if let Some(batch_size) = cli.batch_size {
+        config.parameters.batch_size = batch_size;
+        record_origin(config, cli_origin, String::from("parameters.batch-size"));
+    }
+
+    if let Some(ref log_file) = cli.log_file {
+        config.parameters.log_file = log_file.clone();
+        record_origin(config, cli_origin, String::from("parameters.log-file"));
+    }
+
+    if cli.verbose > 0 || cli.quiet > 0 {
+        config.parameters.log_level = try!(step_log_level(config.parameters.log_level,
+                                                           cli.verbose,
+                                                           cli.quiet));
+        record_origin(config, cli_origin, String::from("parameters.log-level"));
+    }
+
+    Ok(())
+}
+
+/// Record which file resolved `key`, so operators can later tell which layer won.
+fn record_origin(config: &mut Config, path: &Path, key: String) {
+    config.origins.insert(key.clone(),
+                           ConfigOrigin {
+                               file: path.to_path_buf(),
+                               key: key,
+                           });
+}
+
+/// Load every `*.yaml` fragment found in `dir`, in lexicographic filename order, each applied
+/// as its own layer through [`load_path`](fn.load_path.html) so a later fragment extends or
+/// overrides an earlier one (or the main config file). A missing `dir` is skipped silently; an
+/// unreadable or malformed fragment fails with a `ConfigError` naming it.
+fn load_config_dir<P: AsRef<Path>>(dir: P,
+                                    config: &mut Config,
+                                    max_size: Option<u64>)
+                                    -> Result<(), ConfigError> {
+    let dir = dir.as_ref();
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    let entries = try!(fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err)));
+
+    let mut fragments = Vec::new();
+    for entry in entries {
+        let entry = try!(entry.map_err(|err| format!("{}: {}", dir.display(), err)));
+        let path = entry.path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == "yaml") {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+
+    for fragment in &fragments {
+        try!(load_path(fragment, config, max_size));
+    }
+
+    Ok(())
+}
+
 /// Extend confif from file.
-fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), ConfigError> {
-    let mut file = try!(File::open(file_path));
+///
+/// Every value pulled out of `file_path` is recorded into `config.origins`, keyed by its
+/// dotted path (e.g. `sinks.warp10.token`), so a later layer that overrides it simply replaces
+/// the entry. Errors are qualified with `file_path` so a type mismatch or a malformed value can
+/// be traced back to the file responsible; the exception is a `metrics` or `selector` pattern
+/// that fails to compile, which is returned as `ConfigError::Regex` without it. `max_size` rejects a config file bigger than that
+/// many bytes before it is read into memory; `None` disables the check.
+fn load_path<P: AsRef<Path>>(file_path: P,
+                              config: &mut Config,
+                              max_size: Option<u64>)
+                              -> Result<(), ConfigError> {
+    let path = file_path.as_ref();
+    let mut file = try!(File::open(path)
+        .map_err(|err| format!("{}: {}", path.display(), err)));
+
+    if let Some(max_size) = max_size {
+        let size = try!(file.metadata()
+                .map_err(|err| format!("{}: {}", path.display(), err)))
+            .len();
+        if size > max_size {
+            return Err(format!("{}: config file is {} bytes, exceeding the {} byte limit (use \
+                                 --large-config to bypass)",
+                                path.display(),
+                                size,
+                                max_size)
+                .into());
+        }
+    }
+
     let mut contents = String::new();
-    try!(file.read_to_string(&mut contents));
-    let docs = try!(YamlLoader::load_from_str(&contents));
+    try!(file.read_to_string(&mut contents)
+        .map_err(|err| format!("{}: {}", path.display(), err)));
+    let docs = try!(YamlLoader::load_from_str(&contents)
+        .map_err(|err| format!("{}: {}", path.display(), err)));
 
     for doc in &docs {
         if !doc["sources"].is_badvalue() {
             let sources = try!(doc["sources"]
                 .as_hash()
-                .ok_or("sources should be a map"));
+                .ok_or(format!("{}: sources should be a map", path.display())));
 
             for (k, v) in sources {
                 let name = try!(k.as_str()
-                    .ok_or("sources keys should be a string"));
+                    .ok_or(format!("{}: sources keys should be a string", path.display())));
                 let url = try!(v["url"]
                     .as_str()
-                    .ok_or(format!("sources.{}.url is required and should be a string", name)));
+                    .ok_or(format!("{}: sources.{}.url is required and should be a string",
+                                   path.display(),
+                                   name)));
+                let url = try!(expand_env(url,
+                                           &format!("{}: sources.{}.url", path.display(), name)));
                 let period = try!(v["period"]
                     .as_i64()
-                    .ok_or(format!("sources.{}.period is required and should be a number", name)));
+                    .ok_or(format!("{}: sources.{}.period is required and should be a number",
+                                   path.display(),
+                                   name)));
                 let period = try!(cast::u64(period)
-                    .map_err(|_| format!("sources.{}.period is invalid", name)));
+                    .map_err(|_| {
+                        format!("{}: sources.{}.period is invalid", path.display(), name)
+                    }));
                 let format = if v["format"].is_badvalue() {
                     SourceFormat::Prometheus
                 } else {
                     let f = try!(v["format"]
                         .as_str()
-                        .ok_or(format!("sinks.{}.format should be a string", name)));
+                        .ok_or(format!("{}: sources.{}.format should be a string",
+                                       path.display(),
+                                       name)));
+                    let f = try!(expand_env(f,
+                                             &format!("{}: sources.{}.format", path.display(), name)));
 
                     if f == "prometheus" {
                         SourceFormat::Prometheus
                     } else if f == "sensision" {
                         SourceFormat::Sensision
                     } else {
-                        return Err(format!("sinks.{}.format should be 'Prometheus' or 'sensision'",
-                                           name)
+                        return Err(format!("{}: sources.{}.format should be 'prometheus' or \
+                                             'sensision'",
+                                            path.display(),
+                                            name)
                             .into());
                     }
                 };
@@ -228,54 +531,81 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                     None
                 } else {
                     let mut metrics = Vec::new();
-                    let values = try!(v["metrics"].as_vec().ok_or("metrics should be an array"));
+                    let values = try!(v["metrics"]
+                        .as_vec()
+                        .ok_or(format!("{}: metrics should be an array", path.display())));
                     for v in values {
                         let value = try!(regex::Regex::new(try!(v.as_str()
-                            .ok_or(format!("metrics.{} is invalid", name)))));
+                            .ok_or(format!("{}: metrics.{} is invalid", path.display(), name)))));
                         metrics.push(String::from(r"^(\S*)\s") + value.as_str());
                     }
 
                     Some(try!(regex::RegexSet::new(&metrics)))
                 };
 
-                config.sources.push(Source {
+                let source = Source {
                     name: String::from(name),
-                    url: String::from(url),
+                    url: url,
                     period: period,
                     format: format,
                     metrics: metrics,
-                })
+                };
+
+                record_origin(config, path, format!("sources.{}.url", name));
+                record_origin(config, path, format!("sources.{}.period", name));
+                record_origin(config, path, format!("sources.{}.format", name));
+                record_origin(config, path, format!("sources.{}.metrics", name));
+                if let Some(existing) = config.sources.iter_mut().find(|s| s.name == name) {
+                    *existing = source;
+                } else {
+                    config.sources.push(source);
+                }
             }
         }
 
         if !doc["sinks"].is_badvalue() {
-            let sinks = try!(doc["sinks"].as_hash().ok_or("sinks should be a map"));
+            let sinks = try!(doc["sinks"]
+                .as_hash()
+                .ok_or(format!("{}: sinks should be a map", path.display())));
             for (k, v) in sinks {
-                let name = try!(k.as_str().ok_or("sinks keys should be a string"));
+                let name = try!(k.as_str()
+                    .ok_or(format!("{}: sinks keys should be a string", path.display())));
                 let url = try!(v["url"]
                     .as_str()
-                    .ok_or(format!("sinks.{}.url is required and should be a string", name)));
+                    .ok_or(format!("{}: sinks.{}.url is required and should be a string",
+                                   path.display(),
+                                   name)));
+                let url = try!(expand_env(url,
+                                           &format!("{}: sinks.{}.url", path.display(), name)));
                 let token = try!(v["token"]
                     .as_str()
-                    .ok_or(format!("sinks.{}.token is required and should be a string", name)));
+                    .ok_or(format!("{}: sinks.{}.token is required and should be a string",
+                                   path.display(),
+                                   name)));
+                let token = try!(expand_env(token,
+                                             &format!("{}: sinks.{}.token", path.display(), name)));
                 let token_header = if v["token-header"].is_badvalue() {
-                    "X-Warp10-Token"
+                    String::from("X-Warp10-Token")
                 } else {
-                    try!(v["token-header"]
+                    let token_header = try!(v["token-header"]
                         .as_str()
-                        .ok_or(format!("sinks.{}.token-header should be a string", name)))
+                        .ok_or(format!("{}: sinks.{}.token-header should be a string",
+                                       path.display(),
+                                       name)));
+                    try!(expand_env(token_header,
+                                     &format!("{}: sinks.{}.token-header", path.display(), name)))
                 };
 
                 let selector = if v["selector"].is_badvalue() {
                     None
                 } else {
-                    Some(try!(regex::Regex::new(format!("^{}",
-                                                        try!(v["selector"]
-                                                            .as_str()
-                                                            .ok_or(format!("sinks.{}.selector \
-                                                                            is invalid",
-                                                                           name))))
-                        .as_str())))
+                    let selector = try!(v["selector"]
+                        .as_str()
+                        .ok_or(format!("{}: sinks.{}.selector is invalid", path.display(), name)));
+                    let selector =
+                        try!(expand_env(selector,
+                                         &format!("{}: sinks.{}.selector", path.display(), name)));
+                    Some(try!(regex::Regex::new(format!("^{}", selector).as_str())))
                 };
 
                 let ttl = if v["ttl"].is_badvalue() {
@@ -283,9 +613,12 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                 } else {
                     let ttl = try!(v["ttl"]
                         .as_i64()
-                        .ok_or(format!("sinks.{}.ttl should be a number", name)));
-                    try!(cast::u64(ttl)
-                        .map_err(|_| format!("sinks.{}.ttl should be a positive number", name)))
+                        .ok_or(format!("{}: sinks.{}.ttl should be a number", path.display(), name)));
+                    try!(cast::u64(ttl).map_err(|_| {
+                        format!("{}: sinks.{}.ttl should be a positive number",
+                                path.display(),
+                                name)
+                    }))
                 };
 
                 let size = if v["size"].is_badvalue() {
@@ -293,30 +626,55 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                 } else {
                     let size = try!(v["size"]
                         .as_i64()
-                        .ok_or(format!("sinks.{}.size should be a number", name)));
-                    try!(cast::u64(size)
-                        .map_err(|_| format!("sinks.{}.size should be a positive number", name)))
+                        .ok_or(format!("{}: sinks.{}.size should be a number",
+                                       path.display(),
+                                       name)));
+                    try!(cast::u64(size).map_err(|_| {
+                        format!("{}: sinks.{}.size should be a positive number",
+                                path.display(),
+                                name)
+                    }))
                 };
 
-                config.sinks.push(Sink {
+                let sink = Sink {
                     name: String::from(name),
-                    url: String::from(url),
-                    token: String::from(token),
-                    token_header: String::from(token_header),
+                    url: url,
+                    token: token,
+                    token_header: token_header,
                     selector: selector,
                     ttl: ttl,
                     size: size,
-                })
+                };
+
+                record_origin(config, path, format!("sinks.{}.url", name));
+                record_origin(config, path, format!("sinks.{}.token", name));
+                record_origin(config, path, format!("sinks.{}.token-header", name));
+                record_origin(config, path, format!("sinks.{}.selector", name));
+                record_origin(config, path, format!("sinks.{}.ttl", name));
+                record_origin(config, path, format!("sinks.{}.size", name));
+                if let Some(existing) = config.sinks.iter_mut().find(|s| s.name == name) {
+                    *existing = sink;
+                } else {
+                    config.sinks.push(sink);
+                }
             }
         }
 
         if !doc["labels"].is_badvalue() {
-            let labels = try!(doc["labels"].as_hash().ok_or("labels should be a map"));
+            let labels = try!(doc["labels"]
+                .as_hash()
+                .ok_or(format!("{}: labels should be a map", path.display())));
             for (k, v) in labels {
-                let name = try!(k.as_str().ok_or("labels keys should be a string"));
+                let name = try!(k.as_str()
+                    .ok_or(format!("{}: labels keys should be a string", path.display())));
                 let value = try!(v.as_str()
-                    .ok_or(format!("labels.{} value should be a string", name)));
-                config.labels.insert(String::from(name), String::from(value));
+                    .ok_or(format!("{}: labels.{} value should be a string",
+                                   path.display(),
+                                   name)));
+                let value = try!(expand_env(value,
+                                             &format!("{}: labels.{}", path.display(), name)));
+                record_origin(config, path, format!("labels.{}", name));
+                config.labels.insert(String::from(name), value);
             }
         }
 
@@ -324,72 +682,276 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
             if !doc["parameters"]["source-dir"].is_badvalue() {
                 let source_dir = try!(doc["parameters"]["source-dir"]
                     .as_str()
-                    .ok_or(format!("parameters.source-dir should be a string")));
-                config.parameters.source_dir = String::from(source_dir);
+                    .ok_or(format!("{}: parameters.source-dir should be a string",
+                                    path.display())));
+                config.parameters.source_dir =
+                    try!(expand_env(source_dir,
+                                     &format!("{}: parameters.source-dir", path.display())));
+                record_origin(config, path, String::from("parameters.source-dir"));
             }
 
             if !doc["parameters"]["sink-dir"].is_badvalue() {
                 let sink_dir = try!(doc["parameters"]["sink-dir"]
                     .as_str()
-                    .ok_or(format!("parameters.sink-dir should be a string")));
-                config.parameters.sink_dir = String::from(sink_dir);
+                    .ok_or(format!("{}: parameters.sink-dir should be a string", path.display())));
+                config.parameters.sink_dir =
+                    try!(expand_env(sink_dir, &format!("{}: parameters.sink-dir", path.display())));
+                record_origin(config, path, String::from("parameters.sink-dir"));
             }
 
             if !doc["parameters"]["scan-period"].is_badvalue() {
                 let scan_period = try!(doc["parameters"]["scan-period"]
                     .as_i64()
-                    .ok_or(format!("parameters.scan-period should be a number")));
-                let scan_period = try!(cast::u64(scan_period)
-                    .map_err(|_| format!("parameters.scan-period is invalid")));
+                    .ok_or(format!("{}: parameters.scan-period should be a number",
+                                    path.display())));
+                let scan_period = try!(cast::u64(scan_period).map_err(|_| {
+                    format!("{}: parameters.scan-period is invalid", path.display())
+                }));
                 config.parameters.scan_period = scan_period;
+                record_origin(config, path, String::from("parameters.scan-period"));
             }
 
             if !doc["parameters"]["batch-size"].is_badvalue() {
                 let batch_size = try!(doc["parameters"]["batch-size"]
                     .as_i64()
-                    .ok_or(format!("parameters.batch-size should be a number")));
-                let batch_size = try!(cast::u64(batch_size)
-                    .map_err(|_| format!("parameters.batch-size is invalid")));
+                    .ok_or(format!("{}: parameters.batch-size should be a number",
+                                    path.display())));
+                let batch_size = try!(cast::u64(batch_size).map_err(|_| {
+                    format!("{}: parameters.batch-size is invalid", path.display())
+                }));
                 config.parameters.batch_size = batch_size;
+                record_origin(config, path, String::from("parameters.batch-size"));
             }
 
             if !doc["parameters"]["batch-count"].is_badvalue() {
                 let batch_count = try!(doc["parameters"]["batch-count"]
                     .as_i64()
-                    .ok_or(format!("parameters.batch-count should be a number")));
-                let batch_count = try!(cast::u64(batch_count)
-                    .map_err(|_| format!("parameters.batch-count is invalid")));
+                    .ok_or(format!("{}: parameters.batch-count should be a number",
+                                    path.display())));
+                let batch_count = try!(cast::u64(batch_count).map_err(|_| {
+                    format!("{}: parameters.batch-count is invalid", path.display())
+                }));
                 config.parameters.batch_count = batch_count;
+                record_origin(config, path, String::from("parameters.batch-count"));
             }
 
             if !doc["parameters"]["log-file"].is_badvalue() {
                 let log_file = try!(doc["parameters"]["log-file"]
                     .as_str()
-                    .ok_or(format!("parameters.log-file should be a string")));
-                config.parameters.log_file = String::from(log_file);
+                    .ok_or(format!("{}: parameters.log-file should be a string", path.display())));
+                config.parameters.log_file =
+                    try!(expand_env(log_file, &format!("{}: parameters.log-file", path.display())));
+                record_origin(config, path, String::from("parameters.log-file"));
             }
 
             if !doc["parameters"]["log-level"].is_badvalue() {
                 let log_level = try!(doc["parameters"]["log-level"]
                     .as_i64()
-                    .ok_or(format!("parameters.log-level should be a number")));
-                let log_level = try!(cast::u64(log_level)
-                    .map_err(|_| format!("parameters.log-level is invalid")));
+                    .ok_or(format!("{}: parameters.log-level should be a number",
+                                    path.display())));
+                let log_level = try!(cast::u64(log_level).map_err(|_| {
+                    format!("{}: parameters.log-level is invalid", path.display())
+                }));
                 let log_level = try!(slog::Level::from_usize(log_level as usize)
-                    .ok_or(format!("parameters.log-level is invalid")));
+                    .ok_or(format!("{}: parameters.log-level is invalid", path.display())));
                 config.parameters.log_level = log_level;
+                record_origin(config, path, String::from("parameters.log-level"));
             }
 
             if !doc["parameters"]["timeout"].is_badvalue() {
                 let timeout = try!(doc["parameters"]["timeout"]
                     .as_i64()
-                    .ok_or(format!("parameters.timeout should be a number")));
-                let timeout = try!(cast::u64(timeout)
-                    .map_err(|_| format!("parameters.timeout is invalid")));
+                    .ok_or(format!("{}: parameters.timeout should be a number", path.display())));
+                let timeout = try!(cast::u64(timeout).map_err(|_| {
+                    format!("{}: parameters.timeout is invalid", path.display())
+                }));
                 config.parameters.timeout = timeout;
+                record_origin(config, path, String::from("parameters.timeout"));
+            }
+
+            if !doc["parameters"]["telemetry"].is_badvalue() {
+                let listen = try!(doc["parameters"]["telemetry"]["listen"]
+                    .as_str()
+                    .ok_or(format!("{}: parameters.telemetry.listen is required and should be \
+                                     a string",
+                                    path.display())));
+                let listen = try!(expand_env(listen,
+                                              &format!("{}: parameters.telemetry.listen",
+                                                       path.display())));
+                let listen: SocketAddr = try!(listen.parse().map_err(|_| {
+                    format!("{}: parameters.telemetry.listen should be a socket address (e.g. \
+                             127.0.0.1:9110)",
+                            path.display())
+                }));
+                config.parameters.telemetry = Some(Telemetry { listen: listen });
+                record_origin(config, path, String::from("parameters.telemetry.listen"));
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh, empty directory under the OS temp dir, unique to this test process and call.
+    fn test_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("beamium-test-{}-{}-{}", process::id(), name, id));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn empty_config() -> Config {
+        Config {
+            sources: Vec::new(),
+            labels: HashMap::new(),
+            sinks: Vec::new(),
+            parameters: Parameters {
+                scan_period: 1000,
+                sink_dir: String::new(),
+                source_dir: String::new(),
+                batch_size: 0,
+                batch_count: 0,
+                log_file: String::new(),
+                log_level: slog::Level::Info,
+                timeout: 0,
+                telemetry: None,
+            },
+            origins: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn expand_env_substitutes_braced_and_bare_names() {
+        env::set_var("BEAMIUM_TEST_VAR", "secret");
+        assert_eq!(expand_env("${BEAMIUM_TEST_VAR}", "k").unwrap(), "secret");
+        assert_eq!(expand_env("$BEAMIUM_TEST_VAR", "k").unwrap(), "secret");
+        assert_eq!(expand_env("prefix-$BEAMIUM_TEST_VAR-suffix", "k").unwrap(),
+                   "prefix-secret-suffix");
+        env::remove_var("BEAMIUM_TEST_VAR");
+    }
+
+    #[test]
+    fn expand_env_escapes_double_dollar() {
+        assert_eq!(expand_env("$$HOME", "k").unwrap(), "$HOME");
+        assert_eq!(expand_env("price: $$5", "k").unwrap(), "price: $5");
+    }
+
+    #[test]
+    fn expand_env_reports_key_on_undefined_var() {
+        env::remove_var("BEAMIUM_TEST_UNSET");
+        let err = expand_env("${BEAMIUM_TEST_UNSET}", "sinks.foo.token").unwrap_err();
+        assert!(format!("{}", err).contains("sinks.foo.token"));
+        assert!(format!("{}", err).contains("BEAMIUM_TEST_UNSET"));
+    }
+
+    #[test]
+    fn expand_env_reports_unterminated_brace() {
+        let err = expand_env("${UNCLOSED", "parameters.log-file").unwrap_err();
+        assert!(format!("{}", err).contains("parameters.log-file"));
+        assert!(format!("{}", err).contains("unterminated"));
+    }
+
+    #[test]
+    fn step_log_level_raises_and_lowers() {
+        assert_eq!(step_log_level(slog::Level::Info, 1, 0).unwrap(), slog::Level::Debug);
+        assert_eq!(step_log_level(slog::Level::Info, 0, 1).unwrap(), slog::Level::Warning);
+    }
+
+    #[test]
+    fn step_log_level_clamps_at_trace_and_critical() {
+        assert_eq!(step_log_level(slog::Level::Trace, 5, 0).unwrap(), slog::Level::Trace);
+        assert_eq!(step_log_level(slog::Level::Critical, 0, 5).unwrap(),
+                   slog::Level::Critical);
+    }
+
+    #[test]
+    fn load_config_merges_config_d_alongside_explicit_path() {
+        let dir = test_dir("explicit-config-d");
+        let config_path = write(&dir,
+                                 "main.yaml",
+                                 "sources:\n  a:\n    url: http://a\n    period: 1000\n");
+        fs::create_dir_all(dir.join("config.d")).unwrap();
+        write(&dir.join("config.d"),
+              "10-extra.yaml",
+              "sources:\n  b:\n    url: http://b\n    period: 2000\n");
+
+        let config = load_config(config_path.to_str().unwrap(), &CliOverrides::default()).unwrap();
+
+        assert_eq!(config.sources.len(), 2);
+        assert!(config.sources.iter().any(|s| s.name == "a"));
+        assert!(config.sources.iter().any(|s| s.name == "b"));
+    }
+
+    #[test]
+    fn load_config_tracks_origin_per_sink_field() {
+        let dir = test_dir("sink-origin");
+        let config_path = write(&dir,
+                                 "config.yaml",
+                                 "sinks:\n  warp10:\n    url: http://warp10\n    token: t\n");
+
+        let config = load_config(config_path.to_str().unwrap(), &CliOverrides::default()).unwrap();
+
+        assert_eq!(config.origin_of("sinks.warp10.url").unwrap().file, config_path);
+        assert_eq!(config.origin_of("sinks.warp10.token").unwrap().file, config_path);
+        assert!(config.origin_of("sinks.warp10").is_none());
+    }
+
+    #[test]
+    fn load_path_rejects_oversized_file() {
+        let dir = test_dir("oversized");
+        let config_path = write(&dir, "config.yaml", "sources: {}\n");
+
+        let err = load_path(&config_path, &mut empty_config(), Some(1)).unwrap_err();
+        assert!(format!("{}", err).contains("exceeding the 1 byte limit"));
+    }
+
+    #[test]
+    fn validate_config_accepts_well_formed_file() {
+        let dir = test_dir("validate");
+        let config_path = write(&dir, "config.yaml", "parameters:\n  timeout: 30\n");
+        assert!(validate_config(config_path.to_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn validate_config_rejects_malformed_file() {
+        let dir = test_dir("validate-bad");
+        let config_path = write(&dir, "config.yaml", "parameters:\n  timeout: not-a-number\n");
+        assert!(validate_config(config_path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn load_config_parses_telemetry_listen_address() {
+        let dir = test_dir("telemetry");
+        let config_path = write(&dir,
+                                 "config.yaml",
+                                 "parameters:\n  telemetry:\n    listen: 127.0.0.1:9110\n");
+
+        let config = load_config(config_path.to_str().unwrap(), &CliOverrides::default()).unwrap();
+
+        let telemetry = config.parameters.telemetry.unwrap();
+        assert_eq!(telemetry.listen, "127.0.0.1:9110".parse().unwrap());
+    }
+
+    #[test]
+    fn load_config_rejects_invalid_telemetry_listen_address() {
+        let dir = test_dir("telemetry-bad");
+        let config_path = write(&dir,
+                                 "config.yaml",
+                                 "parameters:\n  telemetry:\n    listen: not-an-address\n");
+
+        assert!(load_config(config_path.to_str().unwrap(), &CliOverrides::default()).is_err());
+    }
+}
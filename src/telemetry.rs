@@ -0,0 +1,181 @@
+//! # Telemetry module.
+//!
+//! Exposes beamium's own liveness and internal counters over HTTP, when
+//! `parameters.telemetry` is configured in the [`config`](../config/index.html) module: a
+//! `/health` liveness probe and a `/metrics` endpoint in Prometheus text format, reporting
+//! per-source scrape success/failure counts and per-sink queue depth.
+//!
+//! Call [`start_if_configured`](fn.start_if_configured.html) once at startup with the loaded
+//! `Parameters`; pass the `Arc<Metrics>` it returns down to the source and sink workers so their
+//! `record_scrape` / `set_queue_depth` calls feed what `/metrics` renders.
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use config::Parameters;
+
+#[derive(Debug, Default)]
+/// Scrape success/failure counters for a single source.
+pub struct SourceStats {
+    pub success: u64,
+    pub failure: u64,
+}
+
+#[derive(Debug, Default)]
+/// Queue depth for a single sink.
+pub struct SinkStats {
+    pub queue_depth: u64,
+}
+
+#[derive(Debug, Default)]
+/// Shared telemetry state, updated by the scrape/sink workers and read by the HTTP server.
+pub struct Metrics {
+    pub sources: Mutex<HashMap<String, SourceStats>>,
+    pub sinks: Mutex<HashMap<String, SinkStats>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            sources: Mutex::new(HashMap::new()),
+            sinks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the outcome of a scrape attempt for `source`.
+    pub fn record_scrape(&self, source: &str, success: bool) {
+        let mut sources = self.sources.lock().unwrap();
+        let stats = sources.entry(String::from(source)).or_insert_with(SourceStats::default);
+        if success {
+            stats.success += 1;
+        } else {
+            stats.failure += 1;
+        }
+    }
+
+    /// Record the current queue depth for `sink`.
+    pub fn set_queue_depth(&self, sink: &str, depth: u64) {
+        let mut sinks = self.sinks.lock().unwrap();
+        sinks.entry(String::from(sink)).or_insert_with(SinkStats::default).queue_depth = depth;
+    }
+
+    /// Render the current state in Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP beamium_source_scrape_total Source scrape attempts.\n");
+        out.push_str("# TYPE beamium_source_scrape_total counter\n");
+        for (name, stats) in self.sources.lock().unwrap().iter() {
+            out.push_str(&format!("beamium_source_scrape_total{{source=\"{}\",result=\"success\"}} \
+                                   {}\n",
+                                   name,
+                                   stats.success));
+            out.push_str(&format!("beamium_source_scrape_total{{source=\"{}\",result=\"failure\"}} \
+                                   {}\n",
+                                   name,
+                                   stats.failure));
+        }
+
+        out.push_str("# HELP beamium_sink_queue_depth Current sink queue depth.\n");
+        out.push_str("# TYPE beamium_sink_queue_depth gauge\n");
+        for (name, stats) in self.sinks.lock().unwrap().iter() {
+            out.push_str(&format!("beamium_sink_queue_depth{{sink=\"{}\"}} {}\n",
+                                   name,
+                                   stats.queue_depth));
+        }
+
+        out
+    }
+}
+
+/// Start the telemetry server if `parameters.telemetry` is set, returning the shared `Metrics`
+/// the caller should hand to its source and sink workers so their `record_scrape` /
+/// `set_queue_depth` calls actually populate what `/metrics` renders. Returns `None` if
+/// telemetry isn't configured.
+pub fn start_if_configured(parameters: &Parameters) -> io::Result<Option<Arc<Metrics>>> {
+    match parameters.telemetry {
+        Some(ref telemetry) => {
+            let metrics = Arc::new(Metrics::new());
+            try!(serve(telemetry.listen, metrics.clone()));
+            Ok(Some(metrics))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Start the telemetry HTTP server on `listen`, serving `/health` and `/metrics` off of
+/// `metrics`. Runs on its own thread for the lifetime of the process.
+pub fn serve(listen: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = try!(TcpListener::bind(listen));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let metrics = metrics.clone();
+                thread::spawn(move || handle(stream, &metrics));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Parse the request line out of a minimal HTTP/1.x request and reply with `/health` or
+/// `/metrics`, or a 404 for anything else.
+fn handle(mut stream: TcpStream, metrics: &Metrics) {
+    let mut buf = [0u8; 1024];
+    let read = match stream.read(&mut buf) {
+        Ok(read) => read,
+        Err(_) => return,
+    };
+
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = match path {
+        "/health" => ("200 OK", String::from("OK\n")),
+        "/metrics" => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body);
+    let _ = stream.write_all(response.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Parameters;
+
+    fn empty_parameters(telemetry: Option<::config::Telemetry>) -> Parameters {
+        Parameters {
+            scan_period: 1000,
+            sink_dir: String::new(),
+            source_dir: String::new(),
+            batch_size: 0,
+            batch_count: 0,
+            log_file: String::new(),
+            log_level: ::slog::Level::Info,
+            timeout: 0,
+            telemetry: telemetry,
+        }
+    }
+
+    #[test]
+    fn start_if_configured_is_noop_without_telemetry() {
+        assert!(start_if_configured(&empty_parameters(None)).unwrap().is_none());
+    }
+
+    #[test]
+    fn start_if_configured_serves_when_telemetry_is_set() {
+        let listen: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let parameters = empty_parameters(Some(::config::Telemetry { listen: listen }));
+        let metrics = start_if_configured(&parameters).unwrap();
+        assert!(metrics.is_some());
+    }
+}